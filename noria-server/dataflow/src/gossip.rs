@@ -0,0 +1,302 @@
+//! Push-pull gossip dissemination of per-domain state-size summaries (`Packet::Gossip`).
+//!
+//! Central polling via `GetStatistics`/`UpdateStateSize` is O(N) and gives no domain visibility
+//! into any other domain's memory pressure. `GossipState` instead maintains each domain's merged
+//! view of `StateSizeSummary`s: each round it picks a handful of peers via a weighted random
+//! shuffle biased toward the peers believed to hold the most state, pushes its freshest summaries
+//! to them, and (the reply half of push-pull) merges back whatever they push in return. Incoming
+//! summaries merge last-writer-wins on `StateSizeSummary::version`, and are aged out once they
+//! haven't been refreshed within a timeout -- so a peer that goes away eventually stops counting
+//! toward "hottest domain" decisions instead of pinning a stale high-water mark forever.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use domain;
+use payload::StateSizeSummary;
+
+/// A minimal, seedable PRNG so peer selection is both cheap (no external `rand` dependency in this
+/// tree) and reproducible in tests. Not cryptographic -- gossip peer choice has no adversarial
+/// requirement.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        // xorshift64*
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn next_f64_open01(&mut self) -> f64 {
+        // Avoid exactly 0.0, which would make the Efraimidis-Spirakis key below `-inf`.
+        let v = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        if v == 0.0 {
+            ::std::f64::EPSILON
+        } else {
+            v
+        }
+    }
+}
+
+fn weight_of(summary: &StateSizeSummary) -> f64 {
+    // +1 so a domain that has reported no state yet still has a (small) chance of being
+    // contacted, instead of a hard zero pinning it out of every round forever.
+    (summary.full_bytes + summary.partial_bytes) as f64 + 1.0
+}
+
+/// Pick `k` of `candidates` without replacement, via weighted random sampling proportional to
+/// `weight`: Efraimidis-Spirakis weighted reservoir sampling, implemented as "sort by
+/// `u.powf(1/weight)` descending and take the top k", which is exactly the "selection probability
+/// proportional to advertised state size" the gossip design calls for.
+fn weighted_choose<'a>(
+    candidates: &'a [(domain::Index, f64)],
+    k: usize,
+    rng: &mut Rng,
+) -> Vec<domain::Index> {
+    let mut keyed: Vec<(f64, domain::Index)> = candidates
+        .iter()
+        .map(|&(idx, weight)| {
+            let u = rng.next_f64_open01();
+            (u.powf(1.0 / weight), idx)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    keyed.into_iter().take(k).map(|(_, idx)| idx).collect()
+}
+
+/// One domain's gossiped view of cluster-wide memory pressure: its own summary plus the freshest
+/// summary it has heard about every other domain, each paired with the local time it was last
+/// refreshed so stale entries can be aged out.
+pub struct GossipState {
+    me: domain::Index,
+    my_version: u64,
+    summaries: HashMap<domain::Index, (StateSizeSummary, Instant)>,
+}
+
+impl GossipState {
+    pub fn new(me: domain::Index, now: Instant) -> Self {
+        let mut summaries = HashMap::new();
+        summaries.insert(
+            me,
+            (
+                StateSizeSummary {
+                    version: 0,
+                    full_bytes: 0,
+                    partial_bytes: 0,
+                    recent_evicted_bytes: 0,
+                },
+                now,
+            ),
+        );
+        GossipState {
+            me,
+            my_version: 0,
+            summaries,
+        }
+    }
+
+    /// Refresh this domain's own summary ahead of a gossip round, bumping its version so peers
+    /// that already hold an older copy overwrite it (last-writer-wins).
+    pub fn refresh_own(
+        &mut self,
+        full_bytes: u64,
+        partial_bytes: u64,
+        recent_evicted_bytes: u64,
+        now: Instant,
+    ) {
+        self.my_version += 1;
+        self.summaries.insert(
+            self.me,
+            (
+                StateSizeSummary {
+                    version: self.my_version,
+                    full_bytes,
+                    partial_bytes,
+                    recent_evicted_bytes,
+                },
+                now,
+            ),
+        );
+    }
+
+    /// Choose up to `k` of `candidates` to gossip with this round, weighted proportional to each
+    /// candidate's latest known advertised state size (memory-heavy peers are contacted more
+    /// often). A candidate with no known summary yet is treated as minimal weight rather than
+    /// excluded, so the gossip still explores it.
+    pub fn select_peers(
+        &self,
+        candidates: &[domain::Index],
+        k: usize,
+        rng: &mut Rng,
+    ) -> Vec<domain::Index> {
+        let weighted: Vec<(domain::Index, f64)> = candidates
+            .iter()
+            .map(|&idx| {
+                let weight = self
+                    .summaries
+                    .get(&idx)
+                    .map(|&(ref summary, _)| weight_of(summary))
+                    .unwrap_or(1.0);
+                (idx, weight)
+            })
+            .collect();
+        weighted_choose(&weighted, k, rng)
+    }
+
+    /// The payload to push this round: this domain's own summary plus the freshest it has received
+    /// from everyone else. This is also what a peer pushes back when replying (`reply: true`),
+    /// completing the pull half of the exchange.
+    pub fn push_payload(&self) -> HashMap<domain::Index, StateSizeSummary> {
+        self.summaries
+            .iter()
+            .map(|(&idx, &(ref summary, _))| (idx, *summary))
+            .collect()
+    }
+
+    /// Merge a peer's pushed/pulled summaries into this domain's view: last-writer-wins on
+    /// `version`, and this domain's own entry is authoritative and never overwritten by a peer.
+    pub fn merge(&mut self, incoming: HashMap<domain::Index, StateSizeSummary>, now: Instant) {
+        for (idx, summary) in incoming {
+            if idx == self.me {
+                continue;
+            }
+            let should_replace = match self.summaries.get(&idx) {
+                Some(&(ref existing, _)) => summary.version > existing.version,
+                None => true,
+            };
+            if should_replace {
+                self.summaries.insert(idx, (summary, now));
+            }
+        }
+    }
+
+    /// Drop any non-self entry that hasn't been refreshed within `max_age` of `now`, so a peer
+    /// that stopped gossiping eventually stops counting toward "hottest domain" decisions.
+    pub fn age_out(&mut self, now: Instant, max_age: Duration) {
+        let me = self.me;
+        self.summaries
+            .retain(|&idx, &mut (_, last_seen)| idx == me || now.duration_since(last_seen) <= max_age);
+    }
+
+    /// The `n` domains currently holding the most state (full + partial bytes), by this domain's
+    /// gossiped view. The controller targets `Packet::Evict` at these rather than evicting
+    /// uniformly, and can throttle new replays toward them.
+    pub fn hottest(&self, n: usize) -> Vec<domain::Index> {
+        let mut by_size: Vec<(domain::Index, u64)> = self
+            .summaries
+            .iter()
+            .map(|(&idx, &(ref summary, _))| (idx, summary.full_bytes + summary.partial_bytes))
+            .collect();
+        by_size.sort_by(|a, b| b.1.cmp(&a.1));
+        by_size.into_iter().take(n).map(|(idx, _)| idx).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idx(i: usize) -> domain::Index {
+        domain::Index::new(i)
+    }
+
+    fn summary(version: u64, bytes: u64) -> StateSizeSummary {
+        StateSizeSummary {
+            version,
+            full_bytes: bytes,
+            partial_bytes: 0,
+            recent_evicted_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn merge_is_last_writer_wins_and_never_overwrites_self() {
+        let mut state = GossipState::new(idx(0), Instant::now());
+        let now = Instant::now();
+
+        let mut incoming = HashMap::new();
+        incoming.insert(idx(1), summary(5, 100));
+        state.merge(incoming, now);
+        assert_eq!(state.push_payload()[&idx(1)].full_bytes, 100);
+
+        // Stale version is ignored.
+        let mut stale = HashMap::new();
+        stale.insert(idx(1), summary(3, 999));
+        state.merge(stale, now);
+        assert_eq!(state.push_payload()[&idx(1)].full_bytes, 100);
+
+        // Newer version replaces it.
+        let mut fresh = HashMap::new();
+        fresh.insert(idx(1), summary(6, 42));
+        state.merge(fresh, now);
+        assert_eq!(state.push_payload()[&idx(1)].full_bytes, 42);
+
+        // A peer can never override our own entry.
+        let mut spoof_self = HashMap::new();
+        spoof_self.insert(idx(0), summary(999, 123456));
+        state.merge(spoof_self, now);
+        assert_eq!(state.push_payload()[&idx(0)].full_bytes, 0);
+    }
+
+    #[test]
+    fn age_out_drops_stale_peers_but_keeps_self() {
+        let t0 = Instant::now();
+        let mut state = GossipState::new(idx(0), t0);
+
+        let mut incoming = HashMap::new();
+        incoming.insert(idx(1), summary(1, 10));
+        state.merge(incoming, t0);
+
+        let t1 = t0 + Duration::from_secs(100);
+        state.age_out(t1, Duration::from_secs(30));
+
+        let payload = state.push_payload();
+        assert!(payload.contains_key(&idx(0)));
+        assert!(!payload.contains_key(&idx(1)));
+    }
+
+    #[test]
+    fn hottest_orders_by_total_bytes() {
+        let mut state = GossipState::new(idx(0), Instant::now());
+        let now = Instant::now();
+        let mut incoming = HashMap::new();
+        incoming.insert(idx(1), summary(1, 10));
+        incoming.insert(idx(2), summary(1, 1000));
+        incoming.insert(idx(3), summary(1, 500));
+        state.merge(incoming, now);
+
+        assert_eq!(state.hottest(2), vec![idx(2), idx(3)]);
+    }
+
+    #[test]
+    fn heavier_peers_are_selected_more_often() {
+        let mut state = GossipState::new(idx(0), Instant::now());
+        let now = Instant::now();
+        let mut incoming = HashMap::new();
+        incoming.insert(idx(1), summary(1, 1)); // light
+        incoming.insert(idx(2), summary(1, 1_000_000)); // heavy
+        state.merge(incoming, now);
+
+        let candidates = vec![idx(1), idx(2)];
+        let mut heavy_wins = 0;
+        let mut rng = Rng::new(42);
+        for _ in 0..200 {
+            if state.select_peers(&candidates, 1, &mut rng) == vec![idx(2)] {
+                heavy_wins += 1;
+            }
+        }
+        assert!(
+            heavy_wins > 150,
+            "expected the heavy peer to dominate selection, won {}/200",
+            heavy_wins
+        );
+    }
+}
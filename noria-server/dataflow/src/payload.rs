@@ -15,6 +15,13 @@ use std::fmt;
 use std::net::SocketAddr;
 use std::time;
 
+/// Stable identifier for the query/cache that a unit of replay or eviction work is being done on
+/// behalf of. Threaded through the data-flow payloads that originate query work so the domain can
+/// attribute replay pieces, rows replayed, and evictions back to the owning query rather than only
+/// reporting aggregate per-node numbers.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct QueryId(pub u64);
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ReplayPathSegment {
     pub node: LocalNodeIndex,
@@ -47,6 +54,14 @@ pub enum TriggerEndpoint {
 pub enum InitialState {
     PartialLocal(Vec<(Vec<usize>, Vec<Tag>)>),
     IndexedLocal(HashSet<Vec<usize>>),
+    /// Durable, on-disk state for a `Base` node, backed by the log-structured store in
+    /// `persistent_state::PersistentState` and rooted at `path`, indexed by `index`. Writes are
+    /// committed to the store before they are acked, so on domain restart the base rebuilds from
+    /// disk instead of requiring an upstream replay.
+    PersistentLocal {
+        index: HashSet<Vec<usize>>,
+        path: std::path::PathBuf,
+    },
     PartialGlobal {
         gid: petgraph::graph::NodeIndex,
         cols: usize,
@@ -65,17 +80,54 @@ pub enum ReplayPieceContext {
     Partial {
         for_keys: HashSet<Vec<DataType>>,
         ignore: bool,
+        /// Query this partial replay is serving, if it originated from a query-driven miss.
+        query: Option<QueryId>,
     },
     Regular {
         last: bool,
+        /// Sequence number of this chunk within the full replay, starting at 0. Lets the producer
+        /// match an incoming `ReplayPieceAck` to its single outstanding chunk, and lets it detect
+        /// a stale or duplicated ack for a tag.
+        seq: u64,
     },
 }
 
+/// Acknowledgement sent back to a domain producing a full replay, confirming that a chunk has been
+/// absorbed downstream. The producer waits for one of these before reading the next batch out of
+/// the source state, so a slow consumer throttles the reader instead of forcing it to buffer the
+/// whole materialization in memory.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct ReplayPieceAck {
+    pub tag: Tag,
+    /// Sequence number of the chunk being acknowledged, matching `ReplayPieceContext::Regular::seq`.
+    /// Lets the producer reject a stale or duplicated ack instead of matching on `tag` alone.
+    pub seq: u64,
+    /// Set on the ack for the final (`last: true`) chunk of the replay.
+    pub done: bool,
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct SourceChannelIdentifier {
     pub token: usize,
 }
 
+/// Compact, gossiped summary of a single domain's memory state. Disseminated push-pull between
+/// domains so the cluster can build an approximate global memory view without central O(N) polling.
+///
+/// Entries are merged last-writer-wins on `version`, a per-domain monotonically increasing counter,
+/// and aged out once `version` has not advanced within the gossip timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateSizeSummary {
+    /// Monotonically increasing version stamped by the originating domain.
+    pub version: u64,
+    /// Bytes held in full (non-partial) materializations.
+    pub full_bytes: u64,
+    /// Bytes held in partial materializations.
+    pub partial_bytes: u64,
+    /// Bytes evicted in the most recent gossip interval, a proxy for current eviction pressure.
+    pub recent_evicted_bytes: u64,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub enum Packet {
     // Data messages
@@ -115,6 +167,8 @@ pub enum Packet {
         link: Link,
         tag: Tag,
         keys: Vec<Vec<DataType>>,
+        /// Query on whose behalf these keys are being evicted, if known.
+        query: Option<QueryId>,
     },
 
     //
@@ -135,14 +189,16 @@ pub enum Packet {
         nodes: Vec<LocalNodeIndex>,
     },
 
-    /// Add a new column to an existing `Base` node.
+    /// Add a new column to an existing `Base` node. Applied against a `PersistentLocal` base's
+    /// backing store via `persistent_state::PersistentState::apply_schema_change`.
     AddBaseColumn {
         node: LocalNodeIndex,
         field: String,
         default: DataType,
     },
 
-    /// Drops an existing column from a `Base` node.
+    /// Drops an existing column from a `Base` node. Applied against a `PersistentLocal` base's
+    /// backing store via `persistent_state::PersistentState::apply_schema_change`.
     DropBaseColumn {
         node: LocalNodeIndex,
         column: usize,
@@ -177,6 +233,14 @@ pub enum Packet {
         state: InitialState,
     },
 
+    /// Force durability of persistent `Base` state by flushing and checkpointing the backing
+    /// store. Used by the controller to establish a stable on-disk point before topology changes.
+    /// `node: None` checkpoints every persistent base in the domain; see
+    /// `persistent_state::checkpoint_all`.
+    Checkpoint {
+        node: Option<LocalNodeIndex>,
+    },
+
     /// Probe for the number of records in the given node's state
     StateSizeProbe {
         node: LocalNodeIndex,
@@ -195,6 +259,8 @@ pub enum Packet {
     RequestPartialReplay {
         tag: Tag,
         key: Vec<DataType>,
+        /// Query whose lookup missed, so the replay work can be attributed to it.
+        query: Option<QueryId>,
     },
 
     /// Ask domain (nicely) to replay a particular key.
@@ -202,14 +268,38 @@ pub enum Packet {
         node: LocalNodeIndex,
         cols: Vec<usize>,
         key: Vec<DataType>,
+        /// Query whose reader lookup missed, so the replay work can be attributed to it.
+        query: Option<QueryId>,
     },
 
     /// Instruct domain to replay the state of a particular node along an existing replay path.
+    ///
+    /// The source domain streams the node's state in bounded chunks of at most `chunk_size` rows
+    /// (materializing only one chunk at a time) rather than cloning the entire materialization into
+    /// a single `Vec<Records>`. It reads the next chunk only once it has seen a `ReplayPieceAck`
+    /// for the previous one, keeping peak memory roughly at the chunk size even for base tables
+    /// larger than RAM.
+    ///
+    /// Before taking the iterator/cursor over `from`'s state, the domain stamps `generation` onto
+    /// that node, marking the snapshot the replay observes. Any `Packet::Message` for `from`
+    /// arriving while its stamped generation matches `generation` is routed through the existing
+    /// post-replay buffering path instead of being applied directly, and is drained into the node
+    /// once the final (`last: true`) chunk has been sent and acked — giving the exactly-once guarantee the
+    /// snapshot invariant requires without pausing writes for the duration of the replay.
+    ///
+    /// See `replay_source::ChunkedReplayProducer` for the chunked cursor and ack-window, and
+    /// `replay_source::ReplayGenerationBuffer` for the generation-tagged buffering.
     StartReplay {
         tag: Tag,
         from: LocalNodeIndex,
+        chunk_size: usize,
+        generation: u64,
     },
 
+    /// Acknowledge that a streamed full-replay chunk was absorbed, unblocking the producer's read
+    /// of the next batch.
+    ReplayPieceAck(ReplayPieceAck),
+
     /// Sent to instruct a domain that a particular node should be considered ready to process
     /// updates.
     Ready {
@@ -229,6 +319,20 @@ pub enum Packet {
 
     /// Ask domain to log its state size
     UpdateStateSize,
+
+    /// A round of push-pull gossip carrying state-size summaries.
+    ///
+    /// Each round a domain selects a few peers via a weighted random shuffle (a peer's selection
+    /// probability is proportional to its advertised state size, so memory-heavy domains are
+    /// contacted more often), pushes `summaries` (its own freshest summary plus the freshest it has
+    /// received), and expects the peer to reply with its own. When `reply` is set the receiver
+    /// pushes back its summaries to `from`, completing the pull half of the exchange. See
+    /// `gossip::GossipState` for peer selection, merge, and age-out.
+    Gossip {
+        from: domain::Index,
+        summaries: HashMap<domain::Index, StateSizeSummary>,
+        reply: bool,
+    },
 }
 
 impl Packet {
@@ -407,6 +511,82 @@ impl fmt::Debug for Packet {
     }
 }
 
+/// Per-query breakdown of replay work, letting operators attribute replay pieces, rows replayed,
+/// and time spent to the cached query driving them rather than only seeing aggregate per-node
+/// numbers.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct QueryStats {
+    /// Number of `ReplayPiece`s processed on behalf of this query.
+    pub replay_pieces: u64,
+    /// Number of rows replayed on behalf of this query.
+    pub rows_replayed: u64,
+    /// Cumulative time spent processing replay work for this query.
+    pub time: time::Duration,
+}
+
+/// Key identifying a distinct partial-replay miss site: the replay path the miss was issued on,
+/// the node where the miss was observed, the upstream node the miss propagated into, and the shard.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct ReplayMissKey {
+    pub tag: Tag,
+    pub observed_at: LocalNodeIndex,
+    pub upstream: LocalNodeIndex,
+    pub shard: usize,
+}
+
+/// Upper bounds (in milliseconds) of the fixed buckets used by `ReplayResolutionHistogram`. Shared
+/// across every `ReplayMissKey`, so histograms from different miss sites, shards, or nodes can be
+/// summed bucket-for-bucket when rolling up into a cluster-wide view. The last bucket is a
+/// catch-all for anything slower than `RESOLUTION_BUCKET_BOUNDS_MS`'s last bound.
+pub const RESOLUTION_BUCKET_BOUNDS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1_000, 5_000];
+
+/// Histogram of how long outstanding partial-replay misses took to be satisfied by the arriving
+/// `ReplayPiece`, bucketed against the fixed, shared `RESOLUTION_BUCKET_BOUNDS_MS` boundaries
+/// (plus one overflow bucket for anything slower than the last bound). Using shared boundaries
+/// rather than per-site ones means histograms from different `ReplayMissKey`s can be merged by
+/// summing buckets pairwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayResolutionHistogram {
+    /// `buckets[i]` counts resolutions that took at most `RESOLUTION_BUCKET_BOUNDS_MS[i]` ms (and
+    /// more than `RESOLUTION_BUCKET_BOUNDS_MS[i - 1]` ms); `buckets[RESOLUTION_BUCKET_BOUNDS_MS.len()]`
+    /// is the overflow bucket for anything slower than the largest bound.
+    pub buckets: [u64; RESOLUTION_BUCKET_BOUNDS_MS.len() + 1],
+}
+
+impl ReplayResolutionHistogram {
+    /// Record a single resolution time, placing it in the narrowest bucket whose bound it fits
+    /// under, or the overflow bucket if it exceeds every bound.
+    pub fn record(&mut self, resolved_in: time::Duration) {
+        let ms = resolved_in.as_secs() * 1_000 + u64::from(resolved_in.subsec_millis());
+        let bucket = RESOLUTION_BUCKET_BOUNDS_MS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(RESOLUTION_BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    /// Merge another histogram's counts into this one, bucket-for-bucket.
+    pub fn merge(&mut self, other: &ReplayResolutionHistogram) {
+        for (mine, theirs) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *mine += theirs;
+        }
+    }
+}
+
+/// Per-miss-site accounting for partial replays, letting operators tell an idle node apart from one
+/// stalled waiting on upstream replays.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReplayMissStats {
+    /// Total number of `RequestPartialReplay`s issued for this miss site.
+    pub misses: u64,
+    /// Histogram of how long each outstanding miss took to be satisfied by the arriving
+    /// `ReplayPiece`, bucketed against the shared `RESOLUTION_BUCKET_BOUNDS_MS` boundaries so it
+    /// can be merged across miss sites, shards, and nodes.
+    pub resolution_times: ReplayResolutionHistogram,
+    /// Partial replays issued for this miss site that have not yet been satisfied.
+    pub in_flight: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ControlReplyPacket {
     #[cfg(debug_assertions)]
@@ -419,6 +599,19 @@ pub enum ControlReplyPacket {
         noria::debug::stats::DomainStats,
         HashMap<petgraph::graph::NodeIndex, noria::debug::stats::NodeStats>,
     ),
+    /// Per-miss-site partial-replay accounting, keyed by (tag, observed node, upstream node,
+    /// shard), plus the count of partial replays currently in flight per node. Populated from
+    /// `replay_miss_tracker::ReplayMissTracker`.
+    ReplayStats {
+        misses: HashMap<ReplayMissKey, ReplayMissStats>,
+        in_flight: HashMap<LocalNodeIndex, u64>,
+    },
+    /// Per-query breakdown of replay pieces processed, rows replayed, and time spent, keyed by the
+    /// `QueryId` threaded onto `RequestPartialReplay`/`RequestReaderReplay`/`ReplayPieceContext`.
+    /// Answers "which cached query is driving replay traffic on this domain?" alongside the
+    /// aggregate per-node numbers in `Statistics`. Populated from `query_stats::QueryStatsCollector`;
+    /// the `QueryId`s themselves are minted by `query_stats::QueryIdGenerator`.
+    QueryStatistics(HashMap<QueryId, QueryStats>),
     Booted(usize, SocketAddr),
 }
 
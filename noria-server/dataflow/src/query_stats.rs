@@ -0,0 +1,153 @@
+//! Minting and aggregation of the `QueryId`s threaded onto replay/eviction payloads.
+//!
+//! `QueryId` itself only identifies a query; something has to actually hand them out and fold the
+//! replay work tagged with them into a reportable total. `QueryIdGenerator` does the former,
+//! `QueryStatsCollector` the latter -- its `snapshot` is what a domain would embed in a
+//! `ControlReplyPacket::QueryStatistics` reply.
+//!
+//! Note: the request that introduced `QueryId` asked for the per-query breakdown to live on
+//! `noria::debug::stats::NodeStats`/`DomainStats`. Those types live in the `noria` crate, which
+//! (like the rest of this tree's dependencies) isn't present in this checkout, so they can't be
+//! extended here; `ControlReplyPacket::QueryStatistics` is the reachable equivalent and is what
+//! `snapshot` is meant to feed.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time;
+
+use payload::{QueryId, QueryStats, ReplayPieceContext};
+
+/// Hands out process-unique `QueryId`s. The controller mints one per distinct cached query when
+/// it is installed, and threads it down into the domains that serve it.
+#[derive(Default)]
+pub struct QueryIdGenerator {
+    next: AtomicU64,
+}
+
+impl QueryIdGenerator {
+    pub fn new() -> Self {
+        QueryIdGenerator {
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// Mint the next, never-before-returned `QueryId`.
+    pub fn next_id(&self) -> QueryId {
+        QueryId(self.next.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Per-domain accumulator for `QueryStats`, keyed by `QueryId`. A domain folds the replay work it
+/// does into this as it processes `ReplayPiece`s, and `snapshot` produces the
+/// `HashMap<QueryId, QueryStats>` reported via `ControlReplyPacket::QueryStatistics`.
+#[derive(Default)]
+pub struct QueryStatsCollector {
+    stats: HashMap<QueryId, QueryStats>,
+}
+
+impl QueryStatsCollector {
+    pub fn new() -> Self {
+        QueryStatsCollector::default()
+    }
+
+    /// Fold `rows` rows replayed over `elapsed` time into `query`'s running total.
+    pub fn record(&mut self, query: QueryId, rows: u64, elapsed: time::Duration) {
+        let entry = self.stats.entry(query).or_insert_with(QueryStats::default);
+        entry.replay_pieces += 1;
+        entry.rows_replayed += rows;
+        entry.time += elapsed;
+    }
+
+    /// Attribute a processed `ReplayPiece` to the query named in its `context`, if any. A `Regular`
+    /// (full-replay) piece, or a `Partial` piece with no attributed query, is a no-op: there is
+    /// nothing to attribute it to.
+    pub fn record_replay_piece(
+        &mut self,
+        context: &ReplayPieceContext,
+        rows: u64,
+        elapsed: time::Duration,
+    ) {
+        if let ReplayPieceContext::Partial {
+            query: Some(query), ..
+        } = *context
+        {
+            self.record(query, rows, elapsed);
+        }
+    }
+
+    /// A snapshot of the accumulated per-query stats, suitable for embedding in a
+    /// `ControlReplyPacket::QueryStatistics` reply.
+    pub fn snapshot(&self) -> HashMap<QueryId, QueryStats> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generator_mints_distinct_ids() {
+        let gen = QueryIdGenerator::new();
+        let a = gen.next_id();
+        let b = gen.next_id();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn collector_aggregates_per_query() {
+        let gen = QueryIdGenerator::new();
+        let q1 = gen.next_id();
+        let q2 = gen.next_id();
+
+        let mut collector = QueryStatsCollector::new();
+        collector.record(q1, 10, time::Duration::from_millis(5));
+        collector.record(q1, 20, time::Duration::from_millis(7));
+        collector.record(q2, 1, time::Duration::from_millis(1));
+
+        let snapshot = collector.snapshot();
+        let q1_stats = snapshot.get(&q1).unwrap();
+        assert_eq!(q1_stats.replay_pieces, 2);
+        assert_eq!(q1_stats.rows_replayed, 30);
+        assert_eq!(q1_stats.time, time::Duration::from_millis(12));
+
+        let q2_stats = snapshot.get(&q2).unwrap();
+        assert_eq!(q2_stats.replay_pieces, 1);
+        assert_eq!(q2_stats.rows_replayed, 1);
+    }
+
+    #[test]
+    fn record_replay_piece_ignores_unattributed_work() {
+        let mut collector = QueryStatsCollector::new();
+
+        collector.record_replay_piece(
+            &ReplayPieceContext::Regular { last: true, seq: 0 },
+            5,
+            time::Duration::from_millis(1),
+        );
+        collector.record_replay_piece(
+            &ReplayPieceContext::Partial {
+                for_keys: Default::default(),
+                ignore: false,
+                query: None,
+            },
+            5,
+            time::Duration::from_millis(1),
+        );
+
+        assert!(collector.snapshot().is_empty());
+
+        let gen = QueryIdGenerator::new();
+        let q = gen.next_id();
+        collector.record_replay_piece(
+            &ReplayPieceContext::Partial {
+                for_keys: Default::default(),
+                ignore: false,
+                query: Some(q),
+            },
+            5,
+            time::Duration::from_millis(1),
+        );
+        assert_eq!(collector.snapshot().len(), 1);
+    }
+}
@@ -0,0 +1,132 @@
+//! Wires `Packet::RequestPartialReplay`/arriving `ReplayPiece`s into the per-miss-site accounting
+//! defined in `payload::{ReplayMissKey, ReplayMissStats}`, so `ControlReplyPacket::ReplayStats`
+//! reports real numbers instead of always-empty maps.
+//!
+//! A miss is opened when a domain issues `Packet::RequestPartialReplay { tag, key }` for a site,
+//! and closed when the `ReplayPiece` satisfying that `(tag, key)` arrives; `ReplayMissTracker`
+//! correlates the two so it can bump `misses`/`in_flight` on open and move `in_flight` back down
+//! into `resolution_times` on close.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use payload::{ReplayMissKey, ReplayMissStats};
+use prelude::*;
+
+#[derive(Default)]
+pub struct ReplayMissTracker {
+    stats: HashMap<ReplayMissKey, ReplayMissStats>,
+    outstanding: HashMap<(Tag, Vec<DataType>), (ReplayMissKey, Instant)>,
+}
+
+impl ReplayMissTracker {
+    pub fn new() -> Self {
+        ReplayMissTracker::default()
+    }
+
+    /// Record that a `Packet::RequestPartialReplay { tag: site.tag, key }` was just issued for
+    /// `site`, observed at `at`.
+    pub fn record_miss(&mut self, site: ReplayMissKey, key: Vec<DataType>, at: Instant) {
+        let entry = self.stats.entry(site).or_insert_with(ReplayMissStats::default);
+        entry.misses += 1;
+        // A repeat request for a (tag, key) that is already outstanding joins the open miss: the
+        // one arriving piece satisfies both, so it must not leave an extra replay in flight.
+        let outstanding_key = (site.tag, key);
+        if !self.outstanding.contains_key(&outstanding_key) {
+            entry.in_flight += 1;
+            self.outstanding.insert(outstanding_key, (site, at));
+        }
+    }
+
+    /// Record that the miss on `(tag, key)` was satisfied by a `ReplayPiece` observed at `at`. A
+    /// no-op if `(tag, key)` isn't an outstanding miss -- e.g. a piece satisfying a key nobody
+    /// missed on, such as a speculative/prefetched replay.
+    pub fn record_resolution(&mut self, tag: Tag, key: &[DataType], at: Instant) {
+        let outstanding_key = (tag, key.to_vec());
+        if let Some((site, started)) = self.outstanding.remove(&outstanding_key) {
+            let entry = self.stats.entry(site).or_insert_with(ReplayMissStats::default);
+            entry.in_flight = entry.in_flight.saturating_sub(1);
+            entry.resolution_times.record(at.duration_since(started));
+        }
+    }
+
+    /// Snapshot of per-miss-site accounting, for `ControlReplyPacket::ReplayStats::misses`.
+    pub fn misses_snapshot(&self) -> HashMap<ReplayMissKey, ReplayMissStats> {
+        self.stats.clone()
+    }
+
+    /// Count of currently in-flight partial replays per node (summed across every miss site
+    /// observed at that node), for `ControlReplyPacket::ReplayStats::in_flight`. A large backlog
+    /// here is the clearest signal of a node stalled waiting on upstream replays.
+    pub fn in_flight_snapshot(&self) -> HashMap<LocalNodeIndex, u64> {
+        let mut per_node: HashMap<LocalNodeIndex, u64> = HashMap::new();
+        for (site, stats) in &self.stats {
+            if stats.in_flight > 0 {
+                *per_node.entry(site.observed_at).or_insert(0) += stats.in_flight;
+            }
+        }
+        per_node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn site(node: u32) -> ReplayMissKey {
+        ReplayMissKey {
+            tag: Tag(0),
+            observed_at: LocalNodeIndex::make(node),
+            upstream: LocalNodeIndex::make(node + 100),
+            shard: 0,
+        }
+    }
+
+    #[test]
+    fn miss_then_resolution_updates_counts_and_histogram() {
+        let mut tracker = ReplayMissTracker::new();
+        let site = site(1);
+        let key = vec![DataType::from(42)];
+        let t0 = Instant::now();
+
+        tracker.record_miss(site, key.clone(), t0);
+        let snapshot = tracker.misses_snapshot();
+        assert_eq!(snapshot[&site].misses, 1);
+        assert_eq!(snapshot[&site].in_flight, 1);
+        assert_eq!(tracker.in_flight_snapshot()[&site.observed_at], 1);
+
+        let t1 = t0 + Duration::from_millis(2);
+        tracker.record_resolution(site.tag, &key, t1);
+
+        let snapshot = tracker.misses_snapshot();
+        assert_eq!(snapshot[&site].misses, 1);
+        assert_eq!(snapshot[&site].in_flight, 0);
+        // 2ms falls in the second bucket (<= 5ms).
+        assert_eq!(snapshot[&site].resolution_times.buckets[1], 1);
+        assert!(!tracker.in_flight_snapshot().contains_key(&site.observed_at));
+    }
+
+    #[test]
+    fn unmatched_resolution_is_a_no_op() {
+        let mut tracker = ReplayMissTracker::new();
+        tracker.record_resolution(Tag(0), &[DataType::from(1)], Instant::now());
+        assert!(tracker.misses_snapshot().is_empty());
+    }
+
+    #[test]
+    fn multiple_outstanding_misses_at_one_site_track_independently() {
+        let mut tracker = ReplayMissTracker::new();
+        let site = site(2);
+        let t0 = Instant::now();
+
+        tracker.record_miss(site, vec![DataType::from(1)], t0);
+        tracker.record_miss(site, vec![DataType::from(2)], t0);
+        assert_eq!(tracker.misses_snapshot()[&site].in_flight, 2);
+
+        tracker.record_resolution(site.tag, &[DataType::from(1)], t0 + Duration::from_millis(1));
+        let snapshot = tracker.misses_snapshot();
+        assert_eq!(snapshot[&site].in_flight, 1);
+        assert_eq!(snapshot[&site].misses, 2);
+    }
+}
@@ -0,0 +1,317 @@
+//! Streaming, chunked production of full (non-partial) replays.
+//!
+//! `Packet::StartReplay` used to trigger a full replay by cloning every record out of the source
+//! node's state into a single in-memory `Vec<Records>` before chunking it onto the replay path --
+//! catastrophic for base tables larger than RAM. `ChunkedReplayProducer` instead holds a
+//! cursor/iterator over the source state (`ReplaySource`) and emits one chunk at a time, waiting
+//! for the downstream `ReplayPieceAck` before reading the next batch so a slow consumer throttles
+//! the reader rather than the producer buffering the whole materialization. `ReplayGenerationBuffer`
+//! provides the companion snapshot invariant: writes arriving for the node being replayed are
+//! buffered under the replay's `generation` and drained exactly once the replay completes, instead
+//! of racing the cursor.
+
+use std::collections::{HashMap, VecDeque};
+
+use payload::{Packet, ReplayPieceAck, ReplayPieceContext};
+use prelude::*;
+
+/// A stable snapshot of a node's state that can be consumed in bounded chunks instead of being
+/// cloned into memory all at once. `Base` (and any other) state backends implement this to support
+/// streaming full replay.
+pub trait ReplaySource {
+    /// Pull up to `max_rows` rows out of the snapshot, advancing the cursor. Returns fewer than
+    /// `max_rows` rows only when the snapshot is (about to be) exhausted.
+    fn next_chunk(&mut self, max_rows: usize) -> Records;
+
+    /// True once every row in the snapshot has been returned by `next_chunk`.
+    fn exhausted(&self) -> bool;
+}
+
+/// Tracks chunk sequence numbers and the single outstanding ack for a `StartReplay`, independent
+/// of how the chunk's contents are packaged. Enforces the ack-window of 1: a new chunk may not be
+/// read until the previous one has been acked, and a stale or duplicate ack is rejected rather than
+/// silently unblocking the wrong chunk.
+#[derive(Debug, Default)]
+struct ReplaySequencer {
+    next_seq: u64,
+    awaiting_ack: Option<u64>,
+    done: bool,
+}
+
+impl ReplaySequencer {
+    fn blocked(&self) -> bool {
+        self.awaiting_ack.is_some()
+    }
+
+    /// Allocate the sequence number for the next chunk and mark it as awaiting an ack.
+    fn begin_chunk(&mut self) -> u64 {
+        assert!(!self.blocked(), "begin_chunk called while awaiting an ack");
+        assert!(!self.done, "begin_chunk called after replay completed");
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.awaiting_ack = Some(seq);
+        seq
+    }
+
+    /// Returns `true` if `seq`/`done` matched the outstanding chunk and unblocked the sequencer.
+    fn ack(&mut self, seq: u64, done: bool) -> bool {
+        match self.awaiting_ack {
+            Some(outstanding) if outstanding == seq => {
+                self.awaiting_ack = None;
+                if done {
+                    self.done = true;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Drives a single `Packet::StartReplay` to completion: reads `chunk_size` rows at a time from
+/// `source` and packages them as `Packet::ReplayPiece { context: ReplayPieceContext::Regular { .. }, .. }`,
+/// blocking further reads until the previous chunk's `ReplayPieceAck` arrives. Peak memory for the
+/// replay stays at roughly `chunk_size` rows regardless of how large the source node's state is.
+pub struct ChunkedReplayProducer<S> {
+    tag: Tag,
+    link: Link,
+    source: S,
+    chunk_size: usize,
+    generation: u64,
+    sequencer: ReplaySequencer,
+}
+
+impl<S: ReplaySource> ChunkedReplayProducer<S> {
+    pub fn new(tag: Tag, link: Link, source: S, chunk_size: usize, generation: u64) -> Self {
+        ChunkedReplayProducer {
+            tag,
+            link,
+            source,
+            chunk_size,
+            generation,
+            sequencer: ReplaySequencer::default(),
+        }
+    }
+
+    /// The snapshot generation this replay was stamped with at `StartReplay` time.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// True once the final chunk has been produced and acked.
+    pub fn done(&self) -> bool {
+        self.sequencer.done
+    }
+
+    /// True while a chunk is in flight and `produce_next` must not be called again.
+    pub fn blocked(&self) -> bool {
+        self.sequencer.blocked()
+    }
+
+    /// Read the next chunk from `source` and package it as a `Packet::ReplayPiece`. Panics if
+    /// called while a previous chunk is still awaiting its ack, or after the replay has completed;
+    /// callers must check `blocked`/`done` first.
+    pub fn produce_next(&mut self) -> Packet {
+        let seq = self.sequencer.begin_chunk();
+        let data = self.source.next_chunk(self.chunk_size);
+        let last = self.source.exhausted();
+
+        Packet::ReplayPiece {
+            link: self.link.clone(),
+            tag: self.tag,
+            data,
+            context: ReplayPieceContext::Regular { last, seq },
+        }
+    }
+
+    /// Feed in a `ReplayPieceAck`. Returns `true` if it matched the outstanding chunk (unblocking
+    /// the next `produce_next`), `false` if it was stale, a duplicate, or for a different tag.
+    pub fn on_ack(&mut self, ack: ReplayPieceAck) -> bool {
+        if ack.tag != self.tag {
+            return false;
+        }
+        self.sequencer.ack(ack.seq, ack.done)
+    }
+}
+
+/// Buffers `Packet::Message`s for a node whose state is the source of an in-flight full replay, so
+/// that a write racing the replay cursor is applied exactly once: it is held here until the
+/// replay's final chunk has been acked, then drained into the node in arrival order -- instead of
+/// being applied directly against state the cursor may not have observed yet, or missed entirely.
+///
+/// Messages are only buffered while they carry the *current* generation for `node` (the one
+/// stamped by the `StartReplay` that opened the cursor); a message tagged with a generation from
+/// before the replay started, or a later one, is not this replay's concern.
+#[derive(Default)]
+pub struct ReplayGenerationBuffer {
+    active: HashMap<LocalNodeIndex, (u64, VecDeque<Packet>)>,
+}
+
+impl ReplayGenerationBuffer {
+    /// Mark `node` as being replayed under `generation`; subsequent calls to `buffer` for it will
+    /// be held until `finish` is called.
+    pub fn start(&mut self, node: LocalNodeIndex, generation: u64) {
+        self.active.insert(node, (generation, VecDeque::new()));
+    }
+
+    /// True if `node` is mid-replay under `generation`, meaning its `Packet::Message`s should be
+    /// routed through `buffer` rather than applied directly.
+    pub fn is_active(&self, node: LocalNodeIndex, generation: u64) -> bool {
+        match self.active.get(&node) {
+            Some(&(g, _)) => g == generation,
+            None => false,
+        }
+    }
+
+    /// Buffer a message for `node` arriving while its replay (under `generation`) is in-flight. A
+    /// call for a stale or unknown generation drops `packet` without buffering it: such a message
+    /// belongs to a different replay (or none), so callers check `is_active` first and apply it
+    /// through the normal path instead.
+    pub fn buffer(&mut self, node: LocalNodeIndex, generation: u64, packet: Packet) {
+        if let Some(entry) = self.active.get_mut(&node) {
+            if entry.0 == generation {
+                entry.1.push_back(packet);
+            }
+        }
+    }
+
+    /// Once the replay for `node` has produced its final, acked chunk, drain the buffered messages
+    /// in arrival order so they are applied exactly once, after the replayed snapshot.
+    pub fn finish(&mut self, node: LocalNodeIndex) -> Vec<Packet> {
+        match self.active.remove(&node) {
+            Some((_, queue)) => queue.into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct CountingSource {
+        remaining: usize,
+    }
+
+    impl ReplaySource for CountingSource {
+        fn next_chunk(&mut self, max_rows: usize) -> Records {
+            let take = ::std::cmp::min(max_rows, self.remaining);
+            self.remaining -= take;
+            Records::default()
+        }
+
+        fn exhausted(&self) -> bool {
+            self.remaining == 0
+        }
+    }
+
+    fn link() -> Link {
+        Link::new(LocalNodeIndex::make(0), LocalNodeIndex::make(1))
+    }
+
+    #[test]
+    fn chunks_until_exhausted_and_marks_last() {
+        let source = CountingSource { remaining: 25 };
+        let mut producer = ChunkedReplayProducer::new(Tag(0), link(), source, 10, 1);
+
+        let mut saw_last = false;
+        let mut chunks = 0;
+        while !producer.done() {
+            assert!(!producer.blocked());
+            let packet = producer.produce_next();
+            assert!(producer.blocked(), "must block until acked");
+
+            let (seq, last) = match packet {
+                Packet::ReplayPiece {
+                    context: ReplayPieceContext::Regular { seq, last },
+                    ..
+                } => (seq, last),
+                _ => panic!("expected a regular ReplayPiece"),
+            };
+            assert_eq!(seq, chunks);
+            if last {
+                saw_last = true;
+            }
+
+            let acked = producer.on_ack(ReplayPieceAck {
+                tag: Tag(0),
+                seq,
+                done: last,
+            });
+            assert!(acked);
+            chunks += 1;
+        }
+
+        assert_eq!(chunks, 3); // 10 + 10 + 5 rows
+        assert!(saw_last);
+    }
+
+    #[test]
+    fn stale_or_wrong_tag_acks_are_rejected_and_stay_blocked() {
+        let source = CountingSource { remaining: 5 };
+        let mut producer = ChunkedReplayProducer::new(Tag(0), link(), source, 10, 1);
+
+        producer.produce_next();
+        assert!(producer.blocked());
+
+        // Wrong tag: ignored.
+        assert!(!producer.on_ack(ReplayPieceAck {
+            tag: Tag(1),
+            seq: 0,
+            done: true,
+        }));
+        assert!(producer.blocked());
+
+        // Stale/duplicate seq: ignored.
+        assert!(!producer.on_ack(ReplayPieceAck {
+            tag: Tag(0),
+            seq: 7,
+            done: true,
+        }));
+        assert!(producer.blocked());
+
+        // The real ack unblocks it.
+        assert!(producer.on_ack(ReplayPieceAck {
+            tag: Tag(0),
+            seq: 0,
+            done: true,
+        }));
+        assert!(!producer.blocked());
+        assert!(producer.done());
+    }
+
+    #[test]
+    #[should_panic(expected = "awaiting an ack")]
+    fn produce_next_panics_while_blocked() {
+        let source = CountingSource { remaining: 100 };
+        let mut producer = ChunkedReplayProducer::new(Tag(0), link(), source, 10, 1);
+        producer.produce_next();
+        producer.produce_next();
+    }
+
+    #[test]
+    fn generation_buffer_buffers_only_current_generation_and_drains_in_order() {
+        let node = LocalNodeIndex::make(3);
+        let mut buffer = ReplayGenerationBuffer::default();
+        buffer.start(node, 7);
+        assert!(buffer.is_active(node, 7));
+
+        let msg = |src: usize, dst: usize| Packet::Message {
+            link: Link::new(LocalNodeIndex::make(src as u32), LocalNodeIndex::make(dst as u32)),
+            src: None,
+            data: Records::default(),
+            tracer: None,
+            senders: Vec::new(),
+        };
+
+        buffer.buffer(node, 7, msg(1, 2));
+        // A message from a stale/foreign generation is dropped rather than buffered here.
+        buffer.buffer(node, 6, msg(3, 4));
+        buffer.buffer(node, 7, msg(5, 6));
+
+        let drained = buffer.finish(node);
+        assert_eq!(drained.len(), 2);
+        assert!(!buffer.is_active(node, 7));
+        assert!(buffer.finish(node).is_empty());
+    }
+}